@@ -1,10 +1,18 @@
+//! Needs `uuid` (features `v4`, `serde`), `bincode`, and `sha2` alongside
+//! this crate's existing `anyhow`, `serde`, and `serde_json`, and needs the
+//! `serde` feature enabled on the existing `im` dependency. Cargo.toml must
+//! list all of these before this module will build.
+
 use anyhow::Result;
 use im::HashMap;
 use serde::{Deserialize, Serialize};
 use std::cmp::Eq;
-use std::iter::Peekable;
+use std::collections::HashMap as StdHashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
 use std::{fs, io, path};
+use uuid::Uuid;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -24,6 +32,221 @@ impl EtcFileStatus {
     }
 }
 
+/// The metadata [`Fs::stat`] reports back for a path. A stand-in for
+/// `std::fs::Metadata`, which can't be constructed outside of `std::fs`
+/// itself and so can't be returned by a fake implementation.
+#[derive(Debug, Clone, Copy)]
+pub struct FileStat {
+    pub len: u64,
+    pub modified: std::time::SystemTime,
+    pub is_dir: bool,
+}
+
+/// A random-access file handle, as returned by [`Fs::open_read_write`] and
+/// [`Fs::open_read`]. Blanket-implemented for anything that's `Read + Write +
+/// Seek`, which is all [`EtcTree::append_node`] and [`StoredNode::read_from`]
+/// need from the binary state format's data file.
+pub trait RandomAccessFile: Read + Write + Seek {}
+impl<T: Read + Write + Seek> RandomAccessFile for T {}
+
+/// Abstracts the filesystem operations needed to apply or roll back an
+/// `EtcTree`, so activation/deactivation can be driven by [`RealFs`], a
+/// dry-run [`RecordingFs`] that only records what it would have done, or an
+/// in-memory fake in tests.
+pub trait Fs {
+    fn create_dir(&self, path: &Path) -> io::Result<()>;
+    fn write_file(
+        &self,
+        path: &Path,
+        contents: &[u8],
+        mode: Option<u32>,
+        owner: Option<(u32, u32)>,
+    ) -> io::Result<()>;
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    fn remove_dir(&self, path: &Path) -> io::Result<()>;
+    fn stat(&self, path: &Path) -> io::Result<FileStat>;
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    /// Opens `path` for arbitrary-offset reading and writing, creating it
+    /// if it doesn't already exist, without truncating any existing
+    /// contents. Backs the append-only data file of the binary state
+    /// format, so a dry run never creates or appends to a real file.
+    fn open_read_write(&self, path: &Path) -> io::Result<Box<dyn RandomAccessFile>>;
+    /// Opens `path` for arbitrary-offset reading only.
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn RandomAccessFile>>;
+}
+
+/// The real [`Fs`], backed directly by `std::fs`.
+pub struct RealFs;
+
+impl Fs for RealFs {
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn write_file(
+        &self,
+        path: &Path,
+        contents: &[u8],
+        mode: Option<u32>,
+        owner: Option<(u32, u32)>,
+    ) -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        fs::write(path, contents)?;
+        if let Some(mode) = mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+        if let Some((uid, gid)) = owner {
+            std::os::unix::fs::chown(path, Some(uid), Some(gid))?;
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    fn stat(&self, path: &Path) -> io::Result<FileStat> {
+        let metadata = fs::symlink_metadata(path)?;
+        Ok(FileStat {
+            len: metadata.len(),
+            modified: metadata.modified()?,
+            is_dir: metadata.is_dir(),
+        })
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn open_read_write(&self, path: &Path) -> io::Result<Box<dyn RandomAccessFile>> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn open_read(&self, path: &Path) -> io::Result<Box<dyn RandomAccessFile>> {
+        Ok(Box::new(fs::File::open(path)?))
+    }
+}
+
+/// A single filesystem operation [`RecordingFs`] was asked to perform.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsOperation {
+    CreateDir(PathBuf),
+    WriteFile {
+        path: PathBuf,
+        len: usize,
+        mode: Option<u32>,
+        owner: Option<(u32, u32)>,
+    },
+    RemoveFile(PathBuf),
+    RemoveDir(PathBuf),
+    Rename { from: PathBuf, to: PathBuf },
+    OpenReadWrite(PathBuf),
+}
+
+/// A dry-run [`Fs`]: records every operation it's asked to perform, in
+/// order, instead of touching the real filesystem. Lets a `--dry-run` flag
+/// drive the same `activate`/`deactivate` code paths as a real run and then
+/// report [`Self::operations`] back to the user.
+#[derive(Debug, Default)]
+pub struct RecordingFs {
+    operations: std::cell::RefCell<Vec<FsOperation>>,
+}
+
+impl RecordingFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The operations recorded so far, in the order they were requested.
+    pub fn operations(&self) -> Vec<FsOperation> {
+        self.operations.borrow().clone()
+    }
+}
+
+impl Fs for RecordingFs {
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        self.operations
+            .borrow_mut()
+            .push(FsOperation::CreateDir(path.to_owned()));
+        Ok(())
+    }
+
+    fn write_file(
+        &self,
+        path: &Path,
+        contents: &[u8],
+        mode: Option<u32>,
+        owner: Option<(u32, u32)>,
+    ) -> io::Result<()> {
+        self.operations.borrow_mut().push(FsOperation::WriteFile {
+            path: path.to_owned(),
+            len: contents.len(),
+            mode,
+            owner,
+        });
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.operations
+            .borrow_mut()
+            .push(FsOperation::RemoveFile(path.to_owned()));
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.operations
+            .borrow_mut()
+            .push(FsOperation::RemoveDir(path.to_owned()));
+        Ok(())
+    }
+
+    fn stat(&self, _path: &Path) -> io::Result<FileStat> {
+        // A dry run never actually wrote anything, so there's no real state
+        // to report; treat every path as absent rather than returning stale
+        // or fabricated metadata.
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "RecordingFs does not track real filesystem state",
+        ))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.operations.borrow_mut().push(FsOperation::Rename {
+            from: from.to_owned(),
+            to: to.to_owned(),
+        });
+        Ok(())
+    }
+
+    fn open_read_write(&self, path: &Path) -> io::Result<Box<dyn RandomAccessFile>> {
+        self.operations
+            .borrow_mut()
+            .push(FsOperation::OpenReadWrite(path.to_owned()));
+        Ok(Box::new(io::Cursor::new(Vec::new())))
+    }
+
+    fn open_read(&self, _path: &Path) -> io::Result<Box<dyn RandomAccessFile>> {
+        // Same philosophy as `stat`: a dry run never actually wrote a data
+        // file, so there's nothing real to read back.
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "RecordingFs does not track real filesystem state",
+        ))
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct EtcTree {
@@ -33,6 +256,113 @@ pub struct EtcTree {
     // map. For files the nested map is simple empty.
     // We could potentially optimise this.
     nested: HashMap<String, EtcTree>,
+    /// A snapshot of the real file's metadata, captured at the moment
+    /// system-manager last wrote it. Only consulted on `Managed` nodes:
+    /// [`Self::verify`] skips any other node.
+    #[serde(default)]
+    fingerprint: Option<Fingerprint>,
+    /// The modules/sources that currently claim management of this path.
+    /// Populated by [`Self::merge_trees`]; empty for a tree built solely
+    /// through [`Self::register_managed_entry`].
+    #[serde(default)]
+    owners: im::HashSet<String>,
+}
+
+/// A cheap-to-compare snapshot of a managed file's metadata, captured at the
+/// moment system-manager wrote it, used to detect drift without having to
+/// rehash on every run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Fingerprint {
+    mtime_secs: i64,
+    mtime_nanos: u32,
+    size: u64,
+    hash: Option<[u8; 32]>,
+}
+
+impl Fingerprint {
+    /// Stats and hashes `path` to build a fingerprint suitable
+    /// for later drift detection.
+    pub fn capture(path: &Path) -> io::Result<Self> {
+        let metadata = fs::symlink_metadata(path)?;
+        let mtime = metadata.modified()?;
+        let since_epoch = mtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        Ok(Self {
+            mtime_secs: since_epoch.as_secs() as i64,
+            mtime_nanos: since_epoch.subsec_nanos(),
+            size: metadata.len(),
+            hash: hash_file(path).ok(),
+        })
+    }
+
+    fn agrees_with(&self, other: &Self) -> bool {
+        // Hashing can fail on either side (see `capture`), in which case a
+        // missing hash is "no evidence either way", not disagreement --
+        // same as a missing `Fingerprint` entirely is treated by
+        // `fingerprints_agree`. Only `None` on both sides counts as that,
+        // though: a hash on one side and none on the other is still treated
+        // as a mismatch.
+        match (self.hash, other.hash) {
+            (None, None) => true,
+            (hash, other_hash) => hash.is_some() && hash == other_hash,
+        }
+    }
+
+    fn verify(&self, path: &Path, reference_time: std::time::SystemTime) -> DriftStatus {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return DriftStatus::Missing,
+            Err(_) => return DriftStatus::Missing,
+        };
+
+        if metadata.len() != self.size {
+            return DriftStatus::ModifiedOutOfBand;
+        }
+
+        let reference_secs = reference_time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        let ambiguous = self.mtime_secs == reference_secs;
+
+        if !ambiguous {
+            if let Ok(mtime) = metadata.modified() {
+                let since_epoch = mtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+                if since_epoch.as_secs() as i64 == self.mtime_secs
+                    && since_epoch.subsec_nanos() == self.mtime_nanos
+                {
+                    return DriftStatus::Clean;
+                }
+            }
+        }
+
+        match hash_file(path) {
+            Ok(hash) if self.hash == Some(hash) => DriftStatus::Clean,
+            _ => DriftStatus::ModifiedOutOfBand,
+        }
+    }
+}
+
+/// The outcome of comparing a managed node's recorded [`Fingerprint`] against
+/// the real file on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriftStatus {
+    /// The file matches what system-manager last wrote.
+    Clean,
+    /// The file was changed out-of-band since system-manager last wrote it.
+    ModifiedOutOfBand,
+    /// The file no longer exists.
+    Missing,
+}
+
+fn hash_file(path: &Path) -> io::Result<[u8; 32]> {
+    use sha2::{Digest, Sha256};
+
+    let mut hasher = Sha256::new();
+    let mut reader = io::BufReader::new(fs::File::open(path)?);
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(hasher.finalize().into())
 }
 
 impl AsRef<EtcTree> for EtcTree {
@@ -47,6 +377,25 @@ impl Default for EtcTree {
     }
 }
 
+impl Drop for EtcTree {
+    /// The derived `Drop` glue would recurse through `nested` one stack
+    /// frame per level of tree depth: dropping a node drops its children,
+    /// which drops their children, and so on, regardless of whether the code
+    /// that built or walked the tree was itself iterative. A deep enough
+    /// tree overflows the stack on drop alone. Flatten that onto an explicit
+    /// worklist instead: take each node's `nested` map out before it's
+    /// actually dropped, so by the time the node itself drops it has
+    /// nothing left to recurse into.
+    fn drop(&mut self) {
+        let mut worklist = vec![std::mem::take(&mut self.nested)];
+        while let Some(nested) = worklist.pop() {
+            for (_, mut child) in nested {
+                worklist.push(std::mem::take(&mut child.nested));
+            }
+        }
+    }
+}
+
 /// Data structure to represent files that are managed by system-manager.
 ///
 /// This data will be serialised to disk and read on the next run.
@@ -67,6 +416,8 @@ impl EtcTree {
             status,
             path,
             nested: HashMap::new(),
+            fingerprint: None,
+            owners: im::HashSet::new(),
         }
     }
 
@@ -103,105 +454,267 @@ impl EtcTree {
 
     // TODO is recursion OK here?
     // Should we convert to CPS and use a crate like tramp to TCO this?
+    // Iterative: descend component-by-component, removing each child we pass
+    // through from its parent's `nested` map and remembering (parent, name)
+    // on an explicit stack, then fold the stack back up once we reach the
+    // target, reinserting each rebuilt child into its parent. This produces
+    // the same persistent tree as a recursive `go` helper, but without a
+    // stack frame per path component, so arbitrarily deep paths can't
+    // overflow the stack.
     pub fn register_managed_entry(self, path: &Path) -> Self {
-        fn go<'a, C>(mut tree: EtcTree, mut components: Peekable<C>, path: PathBuf) -> EtcTree
-        where
-            C: Iterator<Item = path::Component<'a>>,
-        {
-            if let Some(component) = components.next() {
-                match component {
-                    path::Component::Normal(name) => {
-                        let new_path = path.join(component);
-                        tree.nested = tree.nested.alter(
-                            |maybe_subtree| {
-                                Some(go(
-                                    maybe_subtree.unwrap_or_else(|| {
-                                        EtcTree::with_status(
-                                            new_path.to_owned(),
-                                            // We only label as managed the final path entry,
-                                            // to label intermediate nodes as managed, we should
-                                            // call this function for every one of them separately.
-                                            components
-                                                .peek()
-                                                .map_or(EtcFileStatus::Managed, |_| {
-                                                    EtcFileStatus::Unmanaged
-                                                }),
-                                        )
-                                    }),
-                                    components,
-                                    new_path,
-                                ))
-                            },
-                            name.to_string_lossy().to_string(),
-                        );
-                        tree
-                    }
-                    path::Component::RootDir => {
-                        go(tree, components, path.join(path::MAIN_SEPARATOR_STR))
-                    }
-                    _ => panic!(
-                        "Unsupported path provided! At path component: {:?}",
-                        component
-                    ),
+        let names: Vec<String> = path
+            .components()
+            .filter_map(|component| match component {
+                path::Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+                path::Component::RootDir => None,
+                _ => panic!("Unsupported path provided! At path component: {:?}", component),
+            })
+            .collect();
+
+        let mut ancestors: Vec<(EtcTree, String)> = Vec::with_capacity(names.len());
+        let mut current_path = PathBuf::from(path::MAIN_SEPARATOR_STR);
+        let mut current = self;
+
+        for (i, name) in names.iter().enumerate() {
+            current_path = current_path.join(name);
+            let is_last = i + 1 == names.len();
+            // Only label as managed the final path entry; to label
+            // intermediate nodes as managed, this should be called for
+            // every one of them separately. An already-existing node's
+            // status is left untouched, matching only-label-new-nodes
+            // behaviour of the original recursive implementation.
+            let child = current.nested.remove(name).unwrap_or_else(|| {
+                EtcTree::with_status(
+                    current_path.clone(),
+                    if is_last {
+                        EtcFileStatus::Managed
+                    } else {
+                        EtcFileStatus::Unmanaged
+                    },
+                )
+            });
+            ancestors.push((current, name.clone()));
+            current = child;
+        }
+
+        while let Some((mut parent, name)) = ancestors.pop() {
+            parent.nested.insert(name, current);
+            current = parent;
+        }
+
+        current
+    }
+
+    /// Removes `source` from the owner set of the node at `path`. A no-op
+    /// if `path` isn't in the tree.
+    ///
+    /// The node's `status` is left as `Managed` even once its last owner is
+    /// gone — emptying the owner set is what makes `deactivate`/
+    /// `update_state` willing to actually remove it next, the same way a
+    /// plain [`Self::register_managed_entry`]-built tree has always worked.
+    ///
+    /// Iterative rather than self-recursive, like [`Self::register_managed_entry`]:
+    /// descend component-by-component onto an explicit `ancestors` stack,
+    /// then fold it back up once the target is reached (or once a missing
+    /// component shows `path` isn't in the tree at all).
+    pub fn unregister_owner(self, path: &Path, source: &str) -> Self {
+        let names: Vec<String> = path
+            .components()
+            .filter_map(|component| match component {
+                path::Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+                path::Component::RootDir => None,
+                _ => panic!("Unsupported path provided! At path component: {:?}", component),
+            })
+            .collect();
+
+        let mut ancestors: Vec<(EtcTree, String)> = Vec::with_capacity(names.len());
+        let mut current = self;
+        let mut found = true;
+
+        for name in &names {
+            match current.nested.remove(name) {
+                Some(child) => {
+                    ancestors.push((current, name.clone()));
+                    current = child;
+                }
+                None => {
+                    found = false;
+                    break;
+                }
+            }
+        }
+
+        if found {
+            current.owners.remove(source);
+        }
+
+        while let Some((mut parent, name)) = ancestors.pop() {
+            parent.nested.insert(name, current);
+            current = parent;
+        }
+
+        current
+    }
+
+    /// Records `fingerprint` on the node at `path`, so a later
+    /// [`Self::verify`] can detect drift. A no-op if `path` isn't in the
+    /// tree.
+    ///
+    /// Iterative rather than self-recursive, like [`Self::register_managed_entry`]
+    /// and [`Self::unregister_owner`].
+    pub fn record_fingerprint(self, path: &Path, fingerprint: Fingerprint) -> Self {
+        let names: Vec<String> = path
+            .components()
+            .filter_map(|component| match component {
+                path::Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+                path::Component::RootDir => None,
+                _ => panic!("Unsupported path provided! At path component: {:?}", component),
+            })
+            .collect();
+
+        let mut ancestors: Vec<(EtcTree, String)> = Vec::with_capacity(names.len());
+        let mut current = self;
+        let mut found = true;
+
+        for name in &names {
+            match current.nested.remove(name) {
+                Some(child) => {
+                    ancestors.push((current, name.clone()));
+                    current = child;
+                }
+                None => {
+                    found = false;
+                    break;
                 }
-            } else {
-                tree
             }
         }
 
-        go(self, path.components().peekable(), PathBuf::new())
+        if found {
+            current.fingerprint = Some(fingerprint);
+        }
+
+        while let Some((mut parent, name)) = ancestors.pop() {
+            parent.nested.insert(name, current);
+            current = parent;
+        }
+
+        current
     }
 
-    pub fn deactivate<F>(self, delete_action: &F) -> Option<EtcTree>
-    where
-        F: Fn(&Path, &EtcFileStatus) -> bool,
-    {
-        let new_tree = self.nested.keys().fold(self.clone(), |mut new_tree, name| {
-            new_tree.nested = new_tree.nested.alter(
-                |subtree| subtree.and_then(|subtree| subtree.deactivate(delete_action)),
-                name.to_owned(),
-            );
-            new_tree
-        });
+    /// Walks the tree and, for every `Managed` node that has a recorded
+    /// [`Fingerprint`], compares it against the real file on disk.
+    ///
+    /// Size and mtime are compared first, since that's cheap; content is only
+    /// rehashed when that comparison is ambiguous, mirroring Mercurial's
+    /// dirstate "disambiguate by length before reading contents" short
+    /// circuit. A node whose stored mtime falls within the same second as
+    /// `reference_time` (typically the state file's own mtime) is treated as
+    /// ambiguous too, so a write and a check that land in the same second
+    /// don't produce a false "clean" result.
+    ///
+    /// Traversal is an explicit stack of `&EtcTree`s rather than
+    /// self-recursion, so it doesn't add a stack frame per level of tree
+    /// depth.
+    pub fn verify(&self, reference_time: std::time::SystemTime) -> Vec<(PathBuf, DriftStatus)> {
+        let mut results = Vec::new();
+        let mut stack = vec![self];
 
-        // We clean up nodes that are empty and unmanaged.
-        // These represent intermediate directories that already existed, so we
-        // are not responsible for cleaning them up (we don't run the delete_action
-        // closure on their paths).
-        if new_tree.nested.is_empty() {
-            if let EtcFileStatus::Managed = new_tree.status {
-                if delete_action(&new_tree.path, &new_tree.status) {
-                    None
-                } else {
-                    Some(new_tree)
+        while let Some(tree) = stack.pop() {
+            if tree.status == EtcFileStatus::Managed {
+                if let Some(fingerprint) = &tree.fingerprint {
+                    results.push((tree.path.clone(), fingerprint.verify(&tree.path, reference_time)));
                 }
-            } else {
-                None
             }
-        } else {
-            Some(new_tree)
+            stack.extend(tree.nested.values());
         }
+
+        results
     }
 
-    pub fn update_state<F>(self, other: Self, delete_action: &F) -> Option<Self>
-    where
-        F: Fn(&Path, &EtcFileStatus) -> bool,
-    {
+    /// Deactivates this whole subtree, removing the real files behind
+    /// managed leaves via `fs`.
+    ///
+    /// Iterative rather than self-recursive: an explicit worklist visits
+    /// every node twice (once to push its children, once to fold their
+    /// already-deactivated results back into it), so walking the tree
+    /// doesn't add a stack frame per level. That alone isn't the whole
+    /// story, though: dropping the `EtcTree`s this produces would still
+    /// recurse through the derived `Drop` glue one frame per level if
+    /// `EtcTree` didn't have its own iterative [`Drop`] impl to flatten it.
+    pub fn deactivate(self, fs: &dyn Fs) -> Option<EtcTree> {
+        enum Step {
+            Enter(EtcTree),
+            Exit(EtcTree, Vec<String>),
+        }
+
+        let mut worklist = vec![Step::Enter(self)];
+        let mut done: Vec<Option<EtcTree>> = Vec::new();
+
+        while let Some(step) = worklist.pop() {
+            match step {
+                Step::Enter(tree) => {
+                    let names: Vec<String> = tree.nested.keys().cloned().collect();
+                    let children: Vec<EtcTree> = names
+                        .iter()
+                        .map(|name| tree.nested.get(name).unwrap().clone())
+                        .collect();
+                    worklist.push(Step::Exit(tree, names));
+                    for child in children {
+                        worklist.push(Step::Enter(child));
+                    }
+                }
+                Step::Exit(mut tree, names) => {
+                    for name in names {
+                        match done.pop().flatten() {
+                            Some(child) => tree.nested.insert(name, child),
+                            None => tree.nested.remove(&name),
+                        };
+                    }
+
+                    // We clean up nodes that are empty and unmanaged. These
+                    // represent intermediate directories that already
+                    // existed, so we are not responsible for cleaning them
+                    // up (we never created them).
+                    let result = if !tree.nested.is_empty() {
+                        Some(tree)
+                    } else if tree.status == EtcFileStatus::Managed && tree.owners.is_empty() {
+                        match fs.remove_file(&tree.path) {
+                            Ok(()) => None,
+                            Err(e) => {
+                                log::warn!("Failed to remove {}: {}", tree.path.display(), e);
+                                Some(tree)
+                            }
+                        }
+                    } else if tree.status == EtcFileStatus::Managed {
+                        // At least one other source still claims this path:
+                        // leave it alone. It's only a candidate for deletion
+                        // again once its last owner is gone via
+                        // `unregister_owner`.
+                        Some(tree)
+                    } else {
+                        None
+                    };
+                    done.push(result);
+                }
+            }
+        }
+
+        done.pop().flatten()
+    }
+
+    pub fn update_state(self, other: Self, fs: &dyn Fs) -> Option<Self> {
         let to_deactivate = other
             .nested
             .clone()
             .relative_complement(self.nested.clone());
-        let to_merge = other.nested.intersection(self.nested.clone());
+        let to_merge = other.nested.clone().intersection(self.nested.clone());
 
         let deactivated = to_deactivate
             .into_iter()
             .fold(self, |mut new_tree, (name, subtree)| {
-                subtree
-                    .deactivate(delete_action)
-                    .into_iter()
-                    .for_each(|subtree| {
-                        new_tree.nested.insert(name.to_owned(), subtree);
-                    });
+                subtree.deactivate(fs).into_iter().for_each(|subtree| {
+                    new_tree.nested.insert(name.to_owned(), subtree);
+                });
                 new_tree
             });
 
@@ -211,7 +724,7 @@ impl EtcTree {
                 new_tree.nested = new_tree.nested.alter(
                     |subtree| {
                         subtree.and_then(|subtree| {
-                            subtree.update_state(other_tree.clone(), delete_action).map(
+                            subtree.update_state(other_tree.clone(), fs).map(
                                 |mut new_tree| {
                                     new_tree.status = new_tree.status.merge(&other_tree.status);
                                     new_tree
@@ -231,6 +744,123 @@ impl EtcTree {
         Some(merged)
     }
 
+    /// Returns an iterator over the changes needed to turn `other` (the
+    /// previous tree) into `self` (the new one), without applying any of
+    /// them. Powers `--dry-run`: the caller can print each [`Change`] instead
+    /// of threading a `delete_action` closure that has side effects.
+    ///
+    /// Both trees' `nested` maps are walked in lockstep, merging their sorted
+    /// key sets level by level and recursing into children present in both,
+    /// much like a merged-tree diff. Traversal is an explicit stack rather
+    /// than function recursion, so yielding is interleaved with walking
+    /// instead of collecting the whole diff up front.
+    pub fn diff<'a>(&'a self, other: &'a EtcTree) -> Diff<'a> {
+        Diff {
+            stack: vec![DiffFrame::Pair(self, other)],
+        }
+    }
+
+    /// Combines one [`EtcTree`] per module/source into a single tree,
+    /// tracking which sources claim each managed path, and reports every
+    /// path that more than one source claims with content that can't be
+    /// shown to agree (judged by their recorded [`Fingerprint`] hashes, when
+    /// both sources have written one).
+    ///
+    /// Conflicted paths are still present in the returned tree (so a caller
+    /// that wants to refuse to apply can do so explicitly), but callers
+    /// should generally treat a non-empty conflict list as reason to not
+    /// activate.
+    pub fn merge_trees(trees: impl IntoIterator<Item = (String, EtcTree)>) -> (Self, Vec<Conflict>) {
+        // Every owner's fingerprint seen so far for a given path, so
+        // `merge_owned` can compare an incoming owner against all of them
+        // instead of just whichever one was merged immediately before it.
+        let mut fingerprint_history: StdHashMap<PathBuf, Vec<(String, Option<Fingerprint>)>> =
+            StdHashMap::new();
+
+        let (combined, conflicts_by_path) = trees.into_iter().fold(
+            (Self::root_node(), StdHashMap::<PathBuf, im::HashSet<String>>::new()),
+            |(combined, mut conflicts_by_path), (source, tree)| {
+                let (combined, new_conflicts) =
+                    combined.merge_owned(&source, tree, &mut fingerprint_history);
+                // A path claimed by three or more owners can raise a
+                // `Conflict` at more than one merge step (e.g. once when b
+                // is merged onto a, and again when c is merged). Merge them
+                // here, keyed by path, so each conflicting path is only
+                // reported once overall.
+                for conflict in new_conflicts {
+                    conflicts_by_path
+                        .entry(conflict.path)
+                        .or_default()
+                        .extend(conflict.owners);
+                }
+                (combined, conflicts_by_path)
+            },
+        );
+
+        let mut conflicts: Vec<Conflict> = conflicts_by_path
+            .into_iter()
+            .map(|(path, owners)| Conflict {
+                path,
+                owners: owners.into_iter().collect(),
+            })
+            .collect();
+        conflicts.sort_by(|a, b| a.path.cmp(&b.path));
+        (combined, conflicts)
+    }
+
+    /// `fingerprint_history` holds every owner's fingerprint merged onto a
+    /// path so far, so a new owner can be compared against all of them --
+    /// not just whichever one happened to be merged immediately before it,
+    /// which would miss disagreements between non-adjacent owners (e.g. a
+    /// vs c, when c happens to agree with b).
+    fn merge_owned(
+        mut self,
+        source: &str,
+        mut other: Self,
+        fingerprint_history: &mut StdHashMap<PathBuf, Vec<(String, Option<Fingerprint>)>>,
+    ) -> (Self, Vec<Conflict>) {
+        let mut conflicts = Vec::new();
+
+        if other.status == EtcFileStatus::Managed {
+            let already_owned = self.status == EtcFileStatus::Managed && !self.owners.is_empty();
+            self.owners.insert(source.to_owned());
+
+            let history = fingerprint_history.entry(self.path.clone()).or_default();
+            let disagrees = already_owned
+                && history
+                    .iter()
+                    .any(|(_, fingerprint)| !fingerprints_agree(fingerprint, &other.fingerprint));
+            if disagrees {
+                conflicts.push(Conflict {
+                    path: self.path.clone(),
+                    owners: self.owners.iter().cloned().collect(),
+                });
+            }
+            history.push((source.to_owned(), other.fingerprint.clone()));
+        }
+        self.status = self.status.merge(&other.status);
+        self.fingerprint = other.fingerprint.clone().or_else(|| self.fingerprint.clone());
+
+        for (name, other_child) in std::mem::take(&mut other.nested) {
+            let mut child_conflicts = Vec::new();
+            self.nested = self.nested.alter(
+                |child| {
+                    let child = child.unwrap_or_else(|| {
+                        EtcTree::with_status(other_child.path.clone(), EtcFileStatus::Unmanaged)
+                    });
+                    let (merged, mut conflicts) =
+                        child.merge_owned(source, other_child, fingerprint_history);
+                    child_conflicts.append(&mut conflicts);
+                    Some(merged)
+                },
+                name,
+            );
+            conflicts.append(&mut child_conflicts);
+        }
+
+        (self, conflicts)
+    }
+
     pub fn write_to_file(&self, state_file: &Path) -> Result<()> {
         log::info!("Writing state info into file: {}", state_file.display());
         let writer = io::BufWriter::new(fs::File::create(state_file)?);
@@ -253,69 +883,522 @@ impl EtcTree {
         }
         Ok(Self::default())
     }
+
+    /// Appends only the subtree blocks that changed since the last write into
+    /// `data_dir`, then atomically points a freshly written docket at
+    /// `docket_file` at the new root block. Unchanged subtrees are left in
+    /// place and simply referenced by their existing offset, so steady-state
+    /// writes stay proportional to the diff rather than to the whole tree.
+    ///
+    /// Once the fraction of the data file that is no longer reachable from
+    /// the root exceeds [`COMPACTION_THRESHOLD`], the whole tree is rewritten
+    /// into a brand-new data file (with a fresh [`Uuid`]) instead, bounding
+    /// how large the append-only log can grow.
+    pub fn write_to_binary_file(&self, docket_file: &Path, data_dir: &Path, fs: &dyn Fs) -> Result<()> {
+        let previous_docket = Docket::read(docket_file).ok();
+
+        let compact = previous_docket
+            .as_ref()
+            .map(|docket| docket.is_due_for_compaction())
+            .unwrap_or(false);
+
+        let (data_file_id, append_offset) = match &previous_docket {
+            Some(docket) if !compact => (docket.data_file_id, docket.total_bytes),
+            _ => (Uuid::new_v4(), 0),
+        };
+
+        fs.create_dir(data_dir)?;
+        let data_path = data_dir.join(data_file_id.to_string());
+        let mut data_file = fs.open_read_write(&data_path)?;
+
+        let mut previous_data_file = match &previous_docket {
+            Some(docket) if !compact => fs.open_read(&docket.data_file_path(data_dir)).ok(),
+            _ => None,
+        };
+
+        let mut offset = append_offset;
+        let mut reused_bytes = 0u64;
+        let root = self.append_node(
+            previous_docket.as_ref().map(|docket| &docket.root).filter(|_| !compact),
+            &mut previous_data_file,
+            data_file.as_mut(),
+            &mut offset,
+            &mut reused_bytes,
+        )?;
+
+        let unreachable_bytes = match &previous_docket {
+            Some(docket) if !compact => docket.total_bytes.saturating_sub(reused_bytes),
+            _ => 0,
+        };
+
+        let docket = Docket {
+            version: BINARY_FORMAT_VERSION,
+            data_file_id,
+            root,
+            unreachable_bytes,
+            total_bytes: offset,
+        };
+        docket.write(docket_file, fs)?;
+
+        // Compaction wrote the whole tree into a fresh data file; the old
+        // one is now unreferenced by the docket we just durably wrote, so
+        // clean it up instead of leaving it to accumulate in `data_dir`.
+        if compact {
+            if let Some(previous_docket) = &previous_docket {
+                let stale_data_path = previous_docket.data_file_path(data_dir);
+                if let Err(e) = fs.remove_file(&stale_data_path) {
+                    log::warn!(
+                        "Failed to remove stale data file {}: {}",
+                        stale_data_path.display(),
+                        e
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads a tree previously written by [`Self::write_to_binary_file`].
+    pub fn from_binary_file(docket_file: &Path, data_dir: &Path) -> Result<Self> {
+        if !docket_file.is_file() {
+            return Ok(Self::default());
+        }
+        let docket = Docket::read(docket_file)?;
+        let mut data_file = fs::File::open(docket.data_file_path(data_dir))?;
+        Self::read_node(&mut data_file, &docket.root)
+    }
+
+    /// Appends this subtree to `data_file` at `*offset`, reusing the block
+    /// recorded at `previous_ref` (read from `previous_data_file`) verbatim
+    /// whenever this node and all of its children are unchanged.
+    fn append_node(
+        &self,
+        previous_ref: Option<&BlockRef>,
+        previous_data_file: &mut Option<Box<dyn RandomAccessFile>>,
+        data_file: &mut dyn RandomAccessFile,
+        offset: &mut u64,
+        reused_bytes: &mut u64,
+    ) -> Result<BlockRef> {
+        let previous_node = match (previous_ref, previous_data_file.as_mut()) {
+            (Some(block_ref), Some(file)) => StoredNode::read_from(file.as_mut(), block_ref).ok(),
+            _ => None,
+        };
+
+        let previous_children: StdHashMap<&str, &BlockRef> = previous_node
+            .as_ref()
+            .map(|node| {
+                node.children
+                    .iter()
+                    .map(|(name, block_ref)| (name.as_str(), block_ref))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut unchanged = previous_node
+            .as_ref()
+            .map(|node| {
+                node.status == self.status
+                    && node.path == self.path
+                    && node.fingerprint == self.fingerprint
+                    && node.owners == self.owners
+                    && node.children.len() == self.nested.len()
+            })
+            .unwrap_or(false);
+
+        let mut children = Vec::with_capacity(self.nested.len());
+        for (name, subtree) in self.nested.iter() {
+            let child_previous_ref = previous_children.get(name.as_str()).copied();
+            let child_ref = subtree.append_node(
+                child_previous_ref,
+                previous_data_file,
+                data_file,
+                offset,
+                reused_bytes,
+            )?;
+            if child_previous_ref != Some(&child_ref) {
+                unchanged = false;
+            }
+            children.push((name.to_owned(), child_ref));
+        }
+
+        if unchanged {
+            let block_ref = previous_ref.unwrap().clone();
+            *reused_bytes += block_ref.len;
+            return Ok(block_ref);
+        }
+
+        let node = StoredNode {
+            status: self.status.clone(),
+            path: self.path.clone(),
+            fingerprint: self.fingerprint.clone(),
+            owners: self.owners.clone(),
+            children,
+        };
+        let block_ref = node.append_to(data_file, *offset)?;
+        *offset += block_ref.len;
+        Ok(block_ref)
+    }
+
+    fn read_node(data_file: &mut fs::File, block_ref: &BlockRef) -> Result<Self> {
+        let node = StoredNode::read_from(data_file, block_ref)?;
+        let mut nested = HashMap::new();
+        for (name, child_ref) in node.children {
+            nested.insert(name, Self::read_node(data_file, &child_ref)?);
+        }
+        Ok(Self {
+            status: node.status,
+            path: node.path,
+            nested,
+            fingerprint: node.fingerprint,
+            owners: node.owners,
+        })
+    }
+}
+
+/// A path claimed by more than one source (via [`EtcTree::merge_trees`])
+/// whose intended content can't be shown to agree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    pub path: PathBuf,
+    pub owners: Vec<String>,
+}
+
+/// Whether two recorded fingerprints give reason to *not* flag a conflict: a
+/// missing fingerprint on either side is unknown rather than disagreement
+/// (e.g. neither owner has ever written the file yet), so it's only `false`
+/// when both sides have recorded one and their hashes differ.
+fn fingerprints_agree(a: &Option<Fingerprint>, b: &Option<Fingerprint>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.agrees_with(b),
+        _ => true,
+    }
+}
+
+/// A single change yielded by [`EtcTree::diff`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// `path` exists in the new tree but not in the old one, with the given
+    /// status. A caller printing a dry-run should generally only act on
+    /// `Managed` ones: `Unmanaged` entries are pure path scaffolding (e.g.
+    /// intermediate directories `register_managed_entry` creates on the way
+    /// to a managed leaf) that was never created or removed on its own.
+    Added(PathBuf, EtcFileStatus),
+    /// `path` existed in the old tree but not in the new one, with the
+    /// status it had. See [`Change::Added`] for why the status matters.
+    Removed(PathBuf, EtcFileStatus),
+    /// `path` exists in both trees, but its status changed from the first
+    /// (old) to the second (new).
+    StatusChanged(PathBuf, EtcFileStatus, EtcFileStatus),
+}
+
+enum DiffFrame<'a> {
+    Pair(&'a EtcTree, &'a EtcTree),
+    Added(&'a EtcTree),
+    Removed(&'a EtcTree),
+}
+
+/// Streaming iterator over the [`Change`]s between two [`EtcTree`]s, produced
+/// by [`EtcTree::diff`].
+pub struct Diff<'a> {
+    stack: Vec<DiffFrame<'a>>,
+}
+
+impl<'a> Iterator for Diff<'a> {
+    type Item = Change;
+
+    fn next(&mut self) -> Option<Change> {
+        loop {
+            match self.stack.pop()? {
+                DiffFrame::Pair(new_tree, old_tree) => {
+                    let mut names: Vec<&String> = new_tree
+                        .nested
+                        .keys()
+                        .chain(old_tree.nested.keys())
+                        .collect();
+                    names.sort();
+                    names.dedup();
+
+                    for name in names.into_iter().rev() {
+                        match (new_tree.nested.get(name), old_tree.nested.get(name)) {
+                            (Some(new_child), Some(old_child)) => {
+                                self.stack.push(DiffFrame::Pair(new_child, old_child))
+                            }
+                            (Some(new_child), None) => {
+                                self.stack.push(DiffFrame::Added(new_child))
+                            }
+                            (None, Some(old_child)) => {
+                                self.stack.push(DiffFrame::Removed(old_child))
+                            }
+                            (None, None) => unreachable!("name came from one of the two maps"),
+                        }
+                    }
+
+                    if new_tree.status != old_tree.status {
+                        return Some(Change::StatusChanged(
+                            new_tree.path.clone(),
+                            old_tree.status.clone(),
+                            new_tree.status.clone(),
+                        ));
+                    }
+                }
+                DiffFrame::Added(tree) => {
+                    for child in tree.nested.values() {
+                        self.stack.push(DiffFrame::Added(child));
+                    }
+                    return Some(Change::Added(tree.path.clone(), tree.status.clone()));
+                }
+                DiffFrame::Removed(tree) => {
+                    for child in tree.nested.values() {
+                        self.stack.push(DiffFrame::Removed(child));
+                    }
+                    return Some(Change::Removed(tree.path.clone(), tree.status.clone()));
+                }
+            }
+        }
+    }
+}
+
+const BINARY_FORMAT_VERSION: u32 = 1;
+
+/// Fraction of a data file's bytes that may sit unreachable (superseded by
+/// later appends) before [`EtcTree::write_to_binary_file`] compacts into a
+/// fresh data file rather than appending again.
+const COMPACTION_THRESHOLD: f64 = 0.5;
+
+/// The byte range of a single appended [`StoredNode`] block within a data
+/// file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct BlockRef {
+    offset: u64,
+    len: u64,
+}
+
+/// The small, rewritten-every-run pointer file of the binary state format.
+///
+/// It names the data file that holds the actual tree (via a random [`Uuid`],
+/// so a concurrent reader can detect that the data file underneath it was
+/// replaced by compaction) and the block within it that is the current root.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Docket {
+    version: u32,
+    data_file_id: Uuid,
+    root: BlockRef,
+    unreachable_bytes: u64,
+    total_bytes: u64,
+}
+
+impl Docket {
+    fn read(path: &Path) -> Result<Self> {
+        let reader = io::BufReader::new(fs::File::open(path)?);
+        let docket: Self = bincode::deserialize_from(reader)?;
+        anyhow::ensure!(
+            docket.version == BINARY_FORMAT_VERSION,
+            "unsupported binary state format version {} (expected {})",
+            docket.version,
+            BINARY_FORMAT_VERSION
+        );
+        Ok(docket)
+    }
+
+    /// Writes the docket atomically: a reader (or a crash) can never observe
+    /// a partially-written docket, since it only ever sees the old one or
+    /// the fully-written new one, never something in between.
+    fn write(&self, path: &Path, fs: &dyn Fs) -> Result<()> {
+        let tmp_path = path.with_extension(format!("{}.tmp", Uuid::new_v4()));
+        let bytes = bincode::serialize(self)?;
+        fs.write_file(&tmp_path, &bytes, None, None)?;
+        fs.rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    fn data_file_path(&self, data_dir: &Path) -> PathBuf {
+        data_dir.join(self.data_file_id.to_string())
+    }
+
+    fn is_due_for_compaction(&self) -> bool {
+        self.total_bytes > 0
+            && (self.unreachable_bytes as f64 / self.total_bytes as f64) > COMPACTION_THRESHOLD
+    }
+}
+
+/// On-disk representation of a single [`EtcTree`] node: its own status and
+/// path, plus the block references of its children. Subtrees are stored by
+/// reference rather than inline so that an unchanged child can be left where
+/// it already is instead of being copied again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredNode {
+    status: EtcFileStatus,
+    path: PathBuf,
+    fingerprint: Option<Fingerprint>,
+    owners: im::HashSet<String>,
+    children: Vec<(String, BlockRef)>,
+}
+
+impl StoredNode {
+    fn read_from(data_file: &mut dyn RandomAccessFile, block_ref: &BlockRef) -> Result<Self> {
+        data_file.seek(SeekFrom::Start(block_ref.offset))?;
+        let mut buf = vec![0u8; block_ref.len as usize];
+        data_file.read_exact(&mut buf)?;
+        Ok(bincode::deserialize(&buf)?)
+    }
+
+    fn append_to(&self, data_file: &mut dyn RandomAccessFile, offset: u64) -> Result<BlockRef> {
+        let bytes = bincode::serialize(self)?;
+        data_file.seek(SeekFrom::Start(offset))?;
+        data_file.write_all(&bytes)?;
+        Ok(BlockRef {
+            offset,
+            len: bytes.len() as u64,
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use itertools::Itertools;
+    use std::time::Duration;
+
+    /// A fresh, empty directory under the OS temp dir, scoped to this
+    /// process and `name` so tests that touch real files don't collide.
+    fn temp_test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "system-manager-etc-tree-test-{}-{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
 
     impl EtcTree {
-        pub fn deactivate_managed_entry<F>(self, path: &Path, delete_action: &F) -> Self
-        where
-            F: Fn(&Path, &EtcFileStatus) -> bool,
-        {
-            fn go<'a, C, F>(
-                mut tree: EtcTree,
-                path: PathBuf,
-                mut components: Peekable<C>,
-                delete_action: &F,
-            ) -> EtcTree
-            where
-                C: Iterator<Item = path::Component<'a>>,
-                F: Fn(&Path, &EtcFileStatus) -> bool,
-            {
-                log::debug!("Deactivating {}", path.display());
-
-                if let Some(component) = components.next() {
-                    match component {
-                        path::Component::Normal(name) => {
-                            let new_path = path.join(name);
-                            tree.nested = tree.nested.alter(
-                                |maybe_subtree| {
-                                    maybe_subtree.and_then(|subtree| {
-                                        if components.peek().is_some() {
-                                            Some(go(subtree, new_path, components, delete_action))
-                                        } else {
-                                            subtree.deactivate(delete_action)
-                                        }
-                                    })
-                                },
-                                name.to_string_lossy().to_string(),
-                            );
-                            tree
+        // Iterative, like `register_managed_entry`: descend to `path`
+        // remembering (parent, name) on an explicit stack, deactivate the
+        // whole subtree found there (via the already-iterative
+        // `EtcTree::deactivate`), then fold the stack back up.
+        pub fn deactivate_managed_entry(self, path: &Path, fs: &dyn Fs) -> Self {
+            let names: Vec<String> = path
+                .components()
+                .filter_map(|component| match component {
+                    path::Component::Normal(name) => Some(name.to_string_lossy().to_string()),
+                    path::Component::RootDir => None,
+                    _ => panic!(
+                        "Unsupported path provided! At path component: {:?}",
+                        component
+                    ),
+                })
+                .collect();
+
+            let mut ancestors: Vec<(EtcTree, String)> = Vec::with_capacity(names.len());
+            let mut current = self;
+
+            for name in &names {
+                log::debug!("Deactivating {}", name);
+                match current.nested.get(name).cloned() {
+                    Some(child) => {
+                        ancestors.push((current, name.clone()));
+                        current = child;
+                    }
+                    // The path isn't present in the tree: nothing to do.
+                    None => {
+                        while let Some((mut parent, name)) = ancestors.pop() {
+                            parent.nested.insert(name, current);
+                            current = parent;
                         }
-                        path::Component::RootDir => go(
-                            tree,
-                            path.join(path::MAIN_SEPARATOR.to_string()),
-                            components,
-                            delete_action,
-                        ),
-                        _ => panic!(
-                            "Unsupported path provided! At path component: {:?}",
-                            component
-                        ),
+                        return current;
                     }
-                } else {
-                    tree
                 }
             }
-            go(
-                self,
-                PathBuf::new(),
-                path.components().peekable(),
-                delete_action,
-            )
+
+            if names.is_empty() {
+                // `path` was just "/"; the original recursive implementation
+                // never deactivated the root itself in this case either.
+                return current;
+            }
+
+            let mut rebuilt = current.deactivate(fs);
+            while let Some((mut parent, name)) = ancestors.pop() {
+                match rebuilt {
+                    Some(child) => parent.nested.insert(name, child),
+                    None => parent.nested.remove(&name),
+                };
+                rebuilt = Some(parent);
+            }
+            rebuilt.unwrap()
+        }
+    }
+
+    /// A fake [`Fs`] for tests: `remove_file` succeeds or fails according
+    /// to `allow`, recording each path it allowed, instead of touching the
+    /// real filesystem.
+    struct FakeFs<F> {
+        removed: std::cell::RefCell<Vec<PathBuf>>,
+        allow: F,
+    }
+
+    impl<F: Fn(&Path) -> bool> FakeFs<F> {
+        fn new(allow: F) -> Self {
+            Self {
+                removed: std::cell::RefCell::new(Vec::new()),
+                allow,
+            }
+        }
+    }
+
+    impl FakeFs<fn(&Path) -> bool> {
+        fn allowing() -> Self {
+            Self::new(|_| true)
+        }
+
+        fn denying() -> Self {
+            Self::new(|_| false)
+        }
+    }
+
+    impl<F: Fn(&Path) -> bool> Fs for FakeFs<F> {
+        fn create_dir(&self, _path: &Path) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn write_file(
+            &self,
+            _path: &Path,
+            _contents: &[u8],
+            _mode: Option<u32>,
+            _owner: Option<(u32, u32)>,
+        ) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn remove_file(&self, path: &Path) -> io::Result<()> {
+            if (self.allow)(path) {
+                self.removed.borrow_mut().push(path.to_owned());
+                Ok(())
+            } else {
+                Err(io::Error::new(io::ErrorKind::PermissionDenied, "denied"))
+            }
+        }
+
+        fn remove_dir(&self, _path: &Path) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn stat(&self, _path: &Path) -> io::Result<FileStat> {
+            Err(io::Error::new(io::ErrorKind::NotFound, "not found"))
+        }
+
+        fn rename(&self, _from: &Path, _to: &Path) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn open_read_write(&self, _path: &Path) -> io::Result<Box<dyn RandomAccessFile>> {
+            Ok(Box::new(io::Cursor::new(Vec::new())))
+        }
+
+        fn open_read(&self, _path: &Path) -> io::Result<Box<dyn RandomAccessFile>> {
+            Err(io::Error::new(io::ErrorKind::NotFound, "not found"))
         }
     }
 
@@ -368,6 +1451,336 @@ mod tests {
         );
     }
 
+    #[test]
+    fn etc_tree_deactivate_with_recording_fs_records_without_touching_disk() {
+        let dir = temp_test_dir("recording-fs-deactivate");
+        let file_path = dir.join("managed-file");
+        fs::write(&file_path, b"hello").unwrap();
+
+        let tree = EtcTree::root_node().register_managed_entry(&file_path);
+        let recording_fs = RecordingFs::new();
+        let result = tree.deactivate(&recording_fs);
+
+        assert!(result.is_none());
+        assert_eq!(
+            recording_fs.operations(),
+            vec![FsOperation::RemoveFile(file_path.clone())]
+        );
+        // A dry run must not actually touch the real file.
+        assert!(file_path.is_file());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn etc_tree_docket_read_rejects_unsupported_version() {
+        let dir = temp_test_dir("binary-bad-version");
+        let docket_file = dir.join("docket");
+
+        let docket = Docket {
+            version: BINARY_FORMAT_VERSION + 1,
+            data_file_id: Uuid::new_v4(),
+            root: BlockRef { offset: 0, len: 0 },
+            unreachable_bytes: 0,
+            total_bytes: 0,
+        };
+        let bytes = bincode::serialize(&docket).unwrap();
+        fs::write(&docket_file, bytes).unwrap();
+
+        assert!(Docket::read(&docket_file).is_err());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn etc_tree_from_binary_file_defaults_when_docket_missing() {
+        let dir = temp_test_dir("binary-missing-docket");
+        let docket_file = dir.join("docket");
+        let data_dir = dir.join("data");
+
+        let tree = EtcTree::from_binary_file(&docket_file, &data_dir).unwrap();
+
+        assert_eq!(tree.nested.keys().collect::<Vec<_>>(), Vec::<&String>::new());
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn etc_tree_binary_round_trip() {
+        let dir = temp_test_dir("binary-round-trip");
+        let docket_file = dir.join("docket");
+        let data_dir = dir.join("data");
+
+        let tree = EtcTree::root_node()
+            .register_managed_entry(&PathBuf::from("/").join("foo").join("bar"))
+            .register_managed_entry(&PathBuf::from("/").join("baz"));
+
+        tree.write_to_binary_file(&docket_file, &data_dir, &RealFs)
+            .unwrap();
+        let read_back = EtcTree::from_binary_file(&docket_file, &data_dir).unwrap();
+
+        assert_eq!(
+            read_back.nested.keys().sorted().collect::<Vec<_>>(),
+            ["baz", "foo"]
+        );
+        assert!(read_back.is_managed(&PathBuf::from("/").join("foo").join("bar")));
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn etc_tree_write_to_binary_file_with_recording_fs_touches_no_disk() {
+        let dir = temp_test_dir("binary-recording-fs");
+        let docket_file = dir.join("docket");
+        let data_dir = dir.join("data");
+
+        let tree = EtcTree::root_node().register_managed_entry(&PathBuf::from("/").join("foo"));
+        let recording_fs = RecordingFs::new();
+        tree.write_to_binary_file(&docket_file, &data_dir, &recording_fs)
+            .unwrap();
+
+        assert!(!docket_file.exists());
+        assert!(!data_dir.exists());
+        assert!(recording_fs
+            .operations()
+            .iter()
+            .any(|op| matches!(op, FsOperation::OpenReadWrite(_))));
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn etc_tree_binary_append_reuses_unchanged_subtree() {
+        let dir = temp_test_dir("binary-append-reuse");
+        let docket_file = dir.join("docket");
+        let data_dir = dir.join("data");
+
+        let tree1 = EtcTree::root_node()
+            .register_managed_entry(&PathBuf::from("/").join("foo"))
+            .register_managed_entry(&PathBuf::from("/").join("baz"));
+        tree1
+            .write_to_binary_file(&docket_file, &data_dir, &RealFs)
+            .unwrap();
+
+        let docket1 = Docket::read(&docket_file).unwrap();
+        let mut data_file1 = fs::File::open(docket1.data_file_path(&data_dir)).unwrap();
+        let root_node1 = StoredNode::read_from(&mut data_file1, &docket1.root).unwrap();
+        let baz_ref1 = root_node1
+            .children
+            .iter()
+            .find(|(name, _)| name == "baz")
+            .unwrap()
+            .1
+            .clone();
+
+        // Only "foo" changes; "baz" should be left untouched on the next write.
+        let tree2 = tree1.register_managed_entry(&PathBuf::from("/").join("foo").join("nested"));
+        tree2
+            .write_to_binary_file(&docket_file, &data_dir, &RealFs)
+            .unwrap();
+
+        let docket2 = Docket::read(&docket_file).unwrap();
+        assert_eq!(docket1.data_file_id, docket2.data_file_id);
+        let mut data_file2 = fs::File::open(docket2.data_file_path(&data_dir)).unwrap();
+        let root_node2 = StoredNode::read_from(&mut data_file2, &docket2.root).unwrap();
+        let baz_ref2 = root_node2
+            .children
+            .iter()
+            .find(|(name, _)| name == "baz")
+            .unwrap()
+            .1
+            .clone();
+
+        assert_eq!(baz_ref1, baz_ref2);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn etc_tree_binary_write_compacts_and_removes_stale_data_file() {
+        let dir = temp_test_dir("binary-compaction");
+        let docket_file = dir.join("docket");
+        let data_dir = dir.join("data");
+
+        let mut docket_ids = Vec::new();
+        for hash_byte in 0u8..4 {
+            let tree = EtcTree::root_node()
+                .register_managed_entry(&PathBuf::from("/").join("managed-file"))
+                .record_fingerprint(
+                    &PathBuf::from("/").join("managed-file"),
+                    Fingerprint {
+                        mtime_secs: 1,
+                        mtime_nanos: 0,
+                        size: 3,
+                        hash: Some([hash_byte; 32]),
+                    },
+                );
+            tree.write_to_binary_file(&docket_file, &data_dir, &RealFs)
+                .unwrap();
+            docket_ids.push(Docket::read(&docket_file).unwrap().data_file_id);
+        }
+
+        // Changing the fingerprint every time means nothing is ever reused,
+        // so at least one of these writes must cross `COMPACTION_THRESHOLD`
+        // and rotate onto a new data file.
+        assert!(docket_ids.windows(2).any(|pair| pair[0] != pair[1]));
+
+        // Whichever write(s) compacted, the stale data file(s) left behind
+        // should have been cleaned up: only the live one remains.
+        let remaining: Vec<_> = fs::read_dir(&data_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        let live_data_path = Docket::read(&docket_file).unwrap().data_file_path(&data_dir);
+        assert_eq!(remaining, vec![live_data_path]);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn etc_tree_fingerprint_verify_clean() {
+        let dir = temp_test_dir("fingerprint-verify-clean");
+        let file_path = dir.join("managed-file");
+        fs::write(&file_path, b"hello").unwrap();
+        let fingerprint = Fingerprint::capture(&file_path).unwrap();
+
+        let far_reference = std::time::SystemTime::now() + Duration::from_secs(3600);
+        assert_eq!(fingerprint.verify(&file_path, far_reference), DriftStatus::Clean);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn etc_tree_fingerprint_verify_modified_out_of_band() {
+        let dir = temp_test_dir("fingerprint-verify-modified");
+        let file_path = dir.join("managed-file");
+        fs::write(&file_path, b"hello").unwrap();
+        let fingerprint = Fingerprint::capture(&file_path).unwrap();
+
+        fs::write(&file_path, b"goodbye, a different length").unwrap();
+
+        let far_reference = std::time::SystemTime::now() + Duration::from_secs(3600);
+        assert_eq!(
+            fingerprint.verify(&file_path, far_reference),
+            DriftStatus::ModifiedOutOfBand
+        );
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn fingerprint_agrees_with_treats_both_hashes_failed_as_agreeing() {
+        let unhashed = Fingerprint {
+            mtime_secs: 1,
+            mtime_nanos: 0,
+            size: 3,
+            hash: None,
+        };
+        let other_unhashed = Fingerprint {
+            mtime_secs: 2,
+            mtime_nanos: 0,
+            size: 5,
+            hash: None,
+        };
+        // Neither side ever managed to hash its file, so there's no
+        // evidence of disagreement -- same as a fingerprint not having been
+        // recorded at all.
+        assert!(unhashed.agrees_with(&other_unhashed));
+
+        let hashed = Fingerprint {
+            mtime_secs: 1,
+            mtime_nanos: 0,
+            size: 3,
+            hash: Some([1; 32]),
+        };
+        // A hash on only one side is still a mismatch: that side does have
+        // evidence, and the other can't corroborate it.
+        assert!(!unhashed.agrees_with(&hashed));
+        assert!(!hashed.agrees_with(&unhashed));
+    }
+
+    #[test]
+    fn etc_tree_fingerprint_verify_missing() {
+        let dir = temp_test_dir("fingerprint-verify-missing");
+        let file_path = dir.join("managed-file");
+        fs::write(&file_path, b"hello").unwrap();
+        let fingerprint = Fingerprint::capture(&file_path).unwrap();
+
+        fs::remove_file(&file_path).unwrap();
+
+        let far_reference = std::time::SystemTime::now() + Duration::from_secs(3600);
+        assert_eq!(fingerprint.verify(&file_path, far_reference), DriftStatus::Missing);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn etc_tree_fingerprint_verify_same_second_as_reference_forces_rehash() {
+        let dir = temp_test_dir("fingerprint-verify-ambiguous");
+        let file_path = dir.join("managed-file");
+        fs::write(&file_path, b"hello").unwrap();
+
+        let metadata = fs::symlink_metadata(&file_path).unwrap();
+        let since_epoch = metadata
+            .modified()
+            .unwrap()
+            .duration_since(UNIX_EPOCH)
+            .unwrap();
+
+        // A fingerprint whose mtime and size match the real file exactly,
+        // but whose hash doesn't -- as if some other write happened to land
+        // on the same mtime and length.
+        let stale = Fingerprint {
+            mtime_secs: since_epoch.as_secs() as i64,
+            mtime_nanos: since_epoch.subsec_nanos(),
+            size: metadata.len(),
+            hash: Some([9; 32]),
+        };
+
+        // Checked well outside the recorded mtime's second: the cheap
+        // mtime/size comparison short-circuits to `Clean` without ever
+        // rehashing, so the mismatched hash goes unnoticed.
+        let far_reference = UNIX_EPOCH + since_epoch + Duration::from_secs(10);
+        assert_eq!(stale.verify(&file_path, far_reference), DriftStatus::Clean);
+
+        // Checked within the same second as the recorded mtime: that's
+        // ambiguous (a write and a check can land in the same second), so
+        // the cheap comparison is skipped in favor of rehashing -- which
+        // catches that the content doesn't actually match.
+        let ambiguous_reference = UNIX_EPOCH + since_epoch;
+        assert_eq!(
+            stale.verify(&file_path, ambiguous_reference),
+            DriftStatus::ModifiedOutOfBand
+        );
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn etc_tree_verify_reports_drift_for_managed_paths() {
+        let dir = temp_test_dir("etc-tree-verify");
+        let file_path = dir.join("managed-file");
+        fs::write(&file_path, b"hello").unwrap();
+        let fingerprint = Fingerprint::capture(&file_path).unwrap();
+
+        let tree = EtcTree::root_node()
+            .register_managed_entry(&file_path)
+            .record_fingerprint(&file_path, fingerprint);
+
+        let far_reference = std::time::SystemTime::now() + Duration::from_secs(3600);
+        let results = tree.verify(far_reference);
+
+        assert_eq!(
+            results
+                .iter()
+                .find(|(path, _)| *path == file_path)
+                .map(|(_, status)| *status),
+            Some(DriftStatus::Clean)
+        );
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
     #[test]
     fn etc_tree_deactivate() {
         let tree1 = EtcTree::root_node()
@@ -387,24 +1800,12 @@ mod tests {
             .register_managed_entry(&PathBuf::from("/").join("foo5").join("baz").join("bar"));
         let tree2 = tree1
             .clone()
-            .deactivate_managed_entry(&PathBuf::from("/").join("foo4"), &|path, _status| {
-                println!("Deactivating: {}", path.display());
-                false
-            })
-            .deactivate_managed_entry(&PathBuf::from("/").join("foo2"), &|path, _status| {
-                println!("Deactivating: {}", path.display());
-                true
-            })
-            .deactivate_managed_entry(&PathBuf::from("/").join("foo3"), &|path, _status| {
-                println!("Deactivating: {}", path.display());
-                true
-            })
+            .deactivate_managed_entry(&PathBuf::from("/").join("foo4"), &FakeFs::denying())
+            .deactivate_managed_entry(&PathBuf::from("/").join("foo2"), &FakeFs::allowing())
+            .deactivate_managed_entry(&PathBuf::from("/").join("foo3"), &FakeFs::allowing())
             .deactivate_managed_entry(
                 &PathBuf::from("/").join("foo5").join("baz"),
-                &|path, _status| {
-                    println!("Deactivating: {}", path.display());
-                    true
-                },
+                &FakeFs::allowing(),
             );
         dbg!(&tree1);
         assert_eq!(
@@ -446,13 +1847,233 @@ mod tests {
             .register_managed_entry(&PathBuf::from("/").join("foo4").join("bar"))
             .register_managed_entry(&PathBuf::from("/").join("foo5"))
             .register_managed_entry(&PathBuf::from("/").join("foo5").join("bar"));
-        let new_tree = tree1.update_state(tree2, &|path, _status| {
-            println!("Deactivating path: {}", path.display());
-            *path != PathBuf::from("/").join("foo5").join("bar")
-        });
+        let new_tree = tree1.update_state(
+            tree2,
+            &FakeFs::new(|path: &Path| *path != PathBuf::from("/").join("foo5").join("bar")),
+        );
         assert_eq!(
             new_tree.unwrap().nested.keys().sorted().collect::<Vec<_>>(),
             ["foo", "foo2", "foo3", "foo5"]
         );
     }
+
+    #[test]
+    fn etc_tree_diff() {
+        let old_tree = EtcTree::root_node()
+            .register_managed_entry(&PathBuf::from("/").join("foo").join("bar"))
+            .register_managed_entry(&PathBuf::from("/").join("baz"));
+        let new_tree = EtcTree::root_node()
+            .register_managed_entry(&PathBuf::from("/").join("foo").join("bar"))
+            .register_managed_entry(&PathBuf::from("/").join("quux"));
+
+        let changes: Vec<Change> = new_tree.diff(&old_tree).collect();
+
+        assert!(changes.contains(&Change::Added(
+            PathBuf::from("/").join("quux"),
+            EtcFileStatus::Managed
+        )));
+        assert!(changes.contains(&Change::Removed(
+            PathBuf::from("/").join("baz"),
+            EtcFileStatus::Managed
+        )));
+        assert!(!changes
+            .iter()
+            .any(|change| matches!(change, Change::StatusChanged(path, ..) if *path == PathBuf::from("/").join("foo").join("bar"))));
+    }
+
+    #[test]
+    fn etc_tree_diff_status_changed() {
+        // "/foo" is an unmanaged intermediate directory in `old_tree` (it
+        // only exists to hold "/foo/bar"), but becomes managed itself in
+        // `new_tree`.
+        let old_tree =
+            EtcTree::root_node().register_managed_entry(&PathBuf::from("/").join("foo").join("bar"));
+        let new_tree = EtcTree::root_node().register_managed_entry(&PathBuf::from("/").join("foo"));
+
+        let changes: Vec<Change> = new_tree.diff(&old_tree).collect();
+
+        assert!(changes.contains(&Change::StatusChanged(
+            PathBuf::from("/").join("foo"),
+            EtcFileStatus::Unmanaged,
+            EtcFileStatus::Managed,
+        )));
+        assert!(changes.contains(&Change::Removed(
+            PathBuf::from("/").join("foo").join("bar"),
+            EtcFileStatus::Managed
+        )));
+    }
+
+    #[test]
+    fn etc_tree_diff_added_carries_status_for_scaffolding_directories() {
+        // Registering "/a/b/c" creates "/a" and "/a/b" as pure, `Unmanaged`
+        // path scaffolding along the way; only "/a/b/c" itself is `Managed`.
+        // A dry-run consumer needs to be able to tell those apart.
+        let old_tree = EtcTree::root_node();
+        let new_tree = EtcTree::root_node()
+            .register_managed_entry(&PathBuf::from("/").join("a").join("b").join("c"));
+
+        let changes: Vec<Change> = new_tree.diff(&old_tree).collect();
+
+        assert!(changes.contains(&Change::Added(
+            PathBuf::from("/").join("a"),
+            EtcFileStatus::Unmanaged
+        )));
+        assert!(changes.contains(&Change::Added(
+            PathBuf::from("/").join("a").join("b"),
+            EtcFileStatus::Unmanaged
+        )));
+        assert!(changes.contains(&Change::Added(
+            PathBuf::from("/").join("a").join("b").join("c"),
+            EtcFileStatus::Managed
+        )));
+    }
+
+    #[test]
+    fn etc_tree_merge_trees_no_conflict_for_disjoint_paths() {
+        let module_a = EtcTree::root_node().register_managed_entry(&PathBuf::from("/").join("foo"));
+        let module_b = EtcTree::root_node().register_managed_entry(&PathBuf::from("/").join("bar"));
+
+        let (combined, conflicts) = EtcTree::merge_trees([
+            ("module-a".to_string(), module_a),
+            ("module-b".to_string(), module_b),
+        ]);
+
+        assert!(conflicts.is_empty());
+        assert!(combined.is_managed(&PathBuf::from("/").join("foo")));
+        assert!(combined.is_managed(&PathBuf::from("/").join("bar")));
+    }
+
+    #[test]
+    fn etc_tree_merge_trees_detects_conflicting_owners() {
+        let module_a = EtcTree::root_node()
+            .register_managed_entry(&PathBuf::from("/").join("foo"))
+            .record_fingerprint(
+                &PathBuf::from("/").join("foo"),
+                Fingerprint {
+                    mtime_secs: 1,
+                    mtime_nanos: 0,
+                    size: 3,
+                    hash: Some([1; 32]),
+                },
+            );
+        let module_b = EtcTree::root_node()
+            .register_managed_entry(&PathBuf::from("/").join("foo"))
+            .record_fingerprint(
+                &PathBuf::from("/").join("foo"),
+                Fingerprint {
+                    mtime_secs: 1,
+                    mtime_nanos: 0,
+                    size: 3,
+                    hash: Some([2; 32]),
+                },
+            );
+
+        let (_combined, conflicts) = EtcTree::merge_trees([
+            ("module-a".to_string(), module_a),
+            ("module-b".to_string(), module_b),
+        ]);
+
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].path, PathBuf::from("/").join("foo"));
+    }
+
+    fn fingerprinted_foo(hash: u8) -> EtcTree {
+        EtcTree::root_node()
+            .register_managed_entry(&PathBuf::from("/").join("foo"))
+            .record_fingerprint(
+                &PathBuf::from("/").join("foo"),
+                Fingerprint {
+                    mtime_secs: 1,
+                    mtime_nanos: 0,
+                    size: 3,
+                    hash: Some([hash; 32]),
+                },
+            )
+    }
+
+    #[test]
+    fn etc_tree_merge_trees_no_conflict_when_fingerprint_not_yet_recorded() {
+        // Neither module has ever written "/foo" yet, so there's no
+        // fingerprint on either side to compare: that's unknown, not
+        // evidence of disagreement, and shouldn't conflict.
+        let module_a = EtcTree::root_node().register_managed_entry(&PathBuf::from("/").join("foo"));
+        let module_b = EtcTree::root_node().register_managed_entry(&PathBuf::from("/").join("foo"));
+
+        let (_combined, conflicts) = EtcTree::merge_trees([
+            ("module-a".to_string(), module_a),
+            ("module-b".to_string(), module_b),
+        ]);
+
+        assert!(conflicts.is_empty());
+    }
+
+    #[test]
+    fn etc_tree_merge_trees_dedupes_conflicts_across_three_owners() {
+        // a and c agree (hash 1); only b disagrees. That should surface as a
+        // single conflict for "/foo", not one per merge step.
+        let module_a = fingerprinted_foo(1);
+        let module_b = fingerprinted_foo(2);
+        let module_c = fingerprinted_foo(1);
+
+        let (_combined, conflicts) = EtcTree::merge_trees([
+            ("module-a".to_string(), module_a),
+            ("module-b".to_string(), module_b),
+            ("module-c".to_string(), module_c),
+        ]);
+
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].path, PathBuf::from("/").join("foo"));
+        assert_eq!(
+            conflicts[0].owners.iter().sorted().collect::<Vec<_>>(),
+            ["module-a", "module-b", "module-c"]
+        );
+    }
+
+    #[test]
+    fn etc_tree_merge_trees_detects_conflict_against_non_adjacent_owner() {
+        // b and c agree (hash 2), but both disagree with a (hash 1). A merge
+        // that only compared each owner against the previously-merged one
+        // would raise a conflict for the a-vs-b step but then miss that c
+        // also disagrees with a, since c happens to match the last
+        // fingerprint (b's) that was merged.
+        let module_a = fingerprinted_foo(1);
+        let module_b = fingerprinted_foo(2);
+        let module_c = fingerprinted_foo(2);
+
+        let (_combined, conflicts) = EtcTree::merge_trees([
+            ("module-a".to_string(), module_a),
+            ("module-b".to_string(), module_b),
+            ("module-c".to_string(), module_c),
+        ]);
+
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].path, PathBuf::from("/").join("foo"));
+        assert_eq!(
+            conflicts[0].owners.iter().sorted().collect::<Vec<_>>(),
+            ["module-a", "module-b", "module-c"]
+        );
+    }
+
+    #[test]
+    fn etc_tree_unregister_owner_keeps_path_while_another_owner_remains() {
+        let module_a = EtcTree::root_node().register_managed_entry(&PathBuf::from("/").join("foo"));
+        let module_b = EtcTree::root_node().register_managed_entry(&PathBuf::from("/").join("foo"));
+
+        let (combined, _conflicts) = EtcTree::merge_trees([
+            ("module-a".to_string(), module_a),
+            ("module-b".to_string(), module_b),
+        ]);
+
+        let after_a_leaves = combined
+            .clone()
+            .unregister_owner(&PathBuf::from("/").join("foo"), "module-a")
+            .deactivate(&FakeFs::allowing())
+            .unwrap();
+        assert!(after_a_leaves.is_managed(&PathBuf::from("/").join("foo")));
+
+        let after_b_leaves = after_a_leaves
+            .unregister_owner(&PathBuf::from("/").join("foo"), "module-b")
+            .deactivate(&FakeFs::allowing());
+        assert!(after_b_leaves.is_none());
+    }
 }